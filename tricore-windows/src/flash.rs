@@ -1,11 +1,353 @@
 use std::process::{Child, Command};
+use std::sync::mpsc;
+use std::time::Duration;
 
 use anyhow::Context;
 use tempfile::TempDir;
 
+/// How long [`MemtoolUpload::wait`] waits for Memtool to finish before
+/// assuming it is stuck (e.g. on a broken flash layout or a stale DAS
+/// connection) and killing it.
+const DEFAULT_FLASH_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// A TriCore AURIX device family supported by Memtool.
+///
+/// Each family has its own `[Controller0]` `Type` and flash bank layout,
+/// mirroring Memtool's own per-target config templates. Clocking is not part
+/// of the family; it is set per board in [`BoardProfile`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceFamily {
+    Tc33x,
+    Tc37x,
+    Tc38x,
+    Tc39x,
+}
+
+impl DeviceFamily {
+    /// The value Memtool expects for `[Controller0] Type`.
+    fn memtool_type(self) -> &'static str {
+        match self {
+            DeviceFamily::Tc33x => "TC33x",
+            DeviceFamily::Tc37x => "TC37xA",
+            DeviceFamily::Tc38x => "TC38xA",
+            DeviceFamily::Tc39x => "TC39xB",
+        }
+    }
+
+    /// The flash banks available on this family and the address range each
+    /// one occupies. PFLASH is sized to each family's largest commonly
+    /// shipped variant (TC334/TC377/TC388/TC399) — smaller-flash variants
+    /// exist within a family, so consult the exact part's datasheet if the
+    /// board uses one.
+    fn flash_banks(self) -> Vec<FlashBank> {
+        let (pflash_size, df_eeprom_end, has_df_ucbs) = match self {
+            DeviceFamily::Tc33x => (0x0020_0000, 0xAF01_0000, false), // 2 MB (TC334)
+            DeviceFamily::Tc37x => (0x0060_0000, 0xAF01_8000, true),  // 6 MB (TC377)
+            DeviceFamily::Tc38x => (0x00A0_0000, 0xAF01_8000, true),  // 10 MB (TC388)
+            DeviceFamily::Tc39x => (0x0100_0000, 0xAF01_8000, true),  // 16 MB (TC399)
+        };
+
+        let mut banks = FlashBank::pflash_aliases(pflash_size).to_vec();
+        banks.push(FlashBank::new("DF_EEPROM", 0xAF00_0000, df_eeprom_end));
+        if has_df_ucbs {
+            banks.push(FlashBank::new("DF_UCBS", 0xAF40_0000, 0xAF40_2000));
+        }
+        banks
+    }
+}
+
+/// A contiguous half-open `[start, end)` range of target addresses, used
+/// both for an ihex's sections and a flash bank's extent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl AddressRange {
+    fn overlaps(&self, other: &AddressRange) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    fn contains(&self, other: &AddressRange) -> bool {
+        self.start <= other.start && other.end <= self.end
+    }
+}
+
+/// One named flash bank on a device, e.g. `PFLASH` or `DF_EEPROM`, and the
+/// address range it occupies.
+#[derive(Debug, Clone, Copy)]
+struct FlashBank {
+    name: &'static str,
+    range: AddressRange,
+}
+
+impl FlashBank {
+    fn new(name: &'static str, start: u32, end: u32) -> Self {
+        FlashBank {
+            name,
+            range: AddressRange { start, end },
+        }
+    }
+
+    /// PFLASH is addressable through both its non-cached alias at
+    /// `0x8000_0000` and its cached alias at `0xA000_0000` (where boot-mode
+    /// headers commonly live); images linked against either alias should be
+    /// recognized as flashable.
+    fn pflash_aliases(size: u32) -> [FlashBank; 2] {
+        [
+            FlashBank::new("PFLASH", 0x8000_0000, 0x8000_0000 + size),
+            FlashBank::new("PFLASH", 0xA000_0000, 0xA000_0000 + size),
+        ]
+    }
+}
+
+/// Describes a specific board to flash: which AURIX family it carries, its
+/// clocking and which flash banks Memtool should enable by default.
+///
+/// This plays the role Memtool's own per-target config templates play: one
+/// `BoardProfile` per board, selecting the `Family`/`Type`/clocks and flash
+/// banks that board exposes.
+#[derive(Debug, Clone)]
+pub struct BoardProfile {
+    pub family: DeviceFamily,
+    pub description: String,
+    pub int_clock: u32,
+    pub ext_clock: u32,
+    pub pflash: bool,
+    pub df_eeprom: bool,
+    pub df_ucbs: bool,
+    pub init_script: InitScript,
+}
+
+impl BoardProfile {
+    /// The Infineon TC39x B-Step Triboard, as previously hardcoded in
+    /// `create_cfg`.
+    pub fn tc39x_triboard() -> Self {
+        BoardProfile {
+            family: DeviceFamily::Tc39x,
+            description: "Triboard with TC39x B-Step (DAS)".to_string(),
+            int_clock: 100_000,
+            ext_clock: 20_000,
+            pflash: true,
+            df_eeprom: true,
+            df_ucbs: true,
+            init_script: InitScript::tlf35584_triboard(),
+        }
+    }
+
+    /// The address ranges of this board's enabled flash banks, used to
+    /// automatically filter out ihex sections that fall outside flashable
+    /// memory.
+    fn flashable_ranges(&self) -> Vec<AddressRange> {
+        self.family
+            .flash_banks()
+            .into_iter()
+            .filter(|bank| match bank.name {
+                "PFLASH" => self.pflash,
+                "DF_EEPROM" => self.df_eeprom,
+                "DF_UCBS" => self.df_ucbs,
+                _ => false,
+            })
+            .map(|bank| bank.range)
+            .collect()
+    }
+}
+
+/// How Memtool should connect to the target before flashing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConnectMode {
+    /// Reset the device and flash non-volatile memory. This is the
+    /// default, previously the only supported behaviour.
+    #[default]
+    Reset,
+    /// Attach to the target without resetting it and download the image
+    /// into RAM instead, then resume execution. Use this to hand an image
+    /// off to an already-initialized target, e.g. one whose first-stage
+    /// bootloader has already set up clocks and DRAM.
+    RamOnly,
+}
+
+impl ConnectMode {
+    /// The value Memtool expects for `[...Tc2CoreTargIntf] ConnOption`.
+    fn conn_option(self) -> &'static str {
+        match self {
+            ConnectMode::Reset => "Reset",
+            ConnectMode::RamOnly => "Attach",
+        }
+    }
+
+    fn download_to_all_rams(self) -> u8 {
+        matches!(self, ConnectMode::RamOnly) as u8
+    }
+
+    fn halt_after_reset(self) -> u8 {
+        matches!(self, ConnectMode::RamOnly) as u8
+    }
+}
+
+/// A single step of an on-connect init script.
+#[derive(Debug, Clone)]
+enum InitStep {
+    /// Writes `value` to the memory-mapped register at `address`.
+    Set { address: u32, value: u32 },
+    /// Waits `millis` milliseconds before the next step.
+    Wait { millis: u32 },
+    /// A `; ...` comment line, kept for readability of the generated config.
+    Comment(String),
+    /// A blank separator line.
+    Blank,
+}
+
+/// An ordered sequence of steps rendered into Memtool's
+/// `[Controller0.Core0.Tc2CoreTargIntf.InitScript]` section and run when it
+/// connects to the target, e.g. to power up a PMIC or mask flash error traps.
+///
+/// This replaces the single hardcoded TLF35584 power-up sequence, letting
+/// callers with a different power-management IC or board bring-up
+/// requirements supply their own.
+#[derive(Debug, Clone, Default)]
+pub struct InitScript {
+    steps: Vec<InitStep>,
+}
+
+impl InitScript {
+    /// An empty init script.
+    pub fn new() -> Self {
+        InitScript { steps: Vec::new() }
+    }
+
+    /// Appends a `SET address value` step.
+    pub fn set(mut self, address: u32, value: u32) -> Self {
+        self.steps.push(InitStep::Set { address, value });
+        self
+    }
+
+    /// Appends a `WAIT millis` step.
+    pub fn wait(mut self, millis: u32) -> Self {
+        self.steps.push(InitStep::Wait { millis });
+        self
+    }
+
+    /// Appends a `; comment` line.
+    pub fn comment(mut self, text: impl Into<String>) -> Self {
+        self.steps.push(InitStep::Comment(text.into()));
+        self
+    }
+
+    /// Appends a blank separator line.
+    pub fn blank(mut self) -> Self {
+        self.steps.push(InitStep::Blank);
+        self
+    }
+
+    /// Renders the steps as the body of an `InitScript` section.
+    fn render(&self) -> String {
+        self.steps
+            .iter()
+            .map(|step| match step {
+                InitStep::Set { address, value } => format!("SET 0x{address:08X} 0x{value:X}"),
+                InitStep::Wait { millis } => format!("WAIT {millis}"),
+                InitStep::Comment(text) => format!("; {text}"),
+                InitStep::Blank => String::new(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// The TLF35584 power-up sequence used by the TC39x B-Step Triboard, and
+    /// which disables the FLASH error traps that would otherwise fire during
+    /// bring-up. Previously hardcoded directly into `create_cfg`.
+    pub fn tlf35584_triboard() -> Self {
+        InitScript::new()
+            .comment("Init TLF35584 C-Step on connect")
+            .set(0xF0036034, 0x11100002)
+            .set(0xF0001E00, 0x8)
+            .set(0xF0001E10, 0x20003C04)
+            .set(0xF0001E04, 0x1)
+            .set(0xF0001E14, 0x14000000)
+            .set(0xF0001E24, 0x501)
+            .set(0xF0001E48, 0x00020000)
+            .set(0xF003AF10, 0x98000000)
+            .set(0xF003AF14, 0x10980000)
+            .set(0xF003AF40, 0x30330333)
+            .set(0xF003AE10, 0x10980000)
+            .set(0xF003AE40, 0x33333033)
+            .wait(5)
+            .set(0xF0001E54, 0xFFF)
+            .set(0xF0001E60, 0x17A10001)
+            .set(0xF0001E54, 0x200)
+            .wait(5)
+            .set(0xF0001E10, 0x21003C04)
+            .set(0xF0001E64, 0x8756)
+            .wait(5)
+            .set(0xF0001E54, 0x200)
+            .wait(5)
+            .set(0xF0001E54, 0x400)
+            .set(0xF0001E64, 0x87DE)
+            .wait(5)
+            .set(0xF0001E54, 0x200)
+            .wait(5)
+            .set(0xF0001E54, 0x400)
+            .set(0xF0001E64, 0x86AD)
+            .wait(5)
+            .set(0xF0001E54, 0x200)
+            .wait(5)
+            .set(0xF0001E54, 0x400)
+            .set(0xF0001E64, 0x8625)
+            .wait(5)
+            .set(0xF0001E54, 0x200)
+            .wait(5)
+            .set(0xF0001E54, 0x400)
+            .set(0xF0001E64, 0x8D27)
+            .wait(5)
+            .set(0xF0001E54, 0x200)
+            .wait(5)
+            .set(0xF0001E54, 0x400)
+            .set(0xF0001E64, 0x8A01)
+            .wait(5)
+            .set(0xF0001E54, 0x200)
+            .wait(5)
+            .set(0xF0001E54, 0x400)
+            .set(0xF0001E64, 0x87BE)
+            .wait(5)
+            .set(0xF0001E54, 0x200)
+            .wait(5)
+            .set(0xF0001E54, 0x400)
+            .set(0xF0001E64, 0x8668)
+            .wait(5)
+            .set(0xF0001E54, 0x200)
+            .wait(5)
+            .set(0xF0001E54, 0x400)
+            .set(0xF0001E64, 0x877D)
+            .wait(5)
+            .set(0xF0001E54, 0x200)
+            .wait(5)
+            .set(0xF0001E54, 0x400)
+            .set(0xF0001E64, 0x8795)
+            .wait(5)
+            .set(0xF0001E54, 0x200)
+            .wait(5)
+            .set(0xF0001E54, 0x400)
+            .wait(5)
+            .blank()
+            .comment("switch off FLASH error traps")
+            .set(0xF8801104, 0x10000)
+            .set(0xF8821104, 0x10000)
+            .set(0xF8841104, 0x10000)
+            .set(0xF8861104, 0x10000)
+            .set(0xF8881104, 0x10000)
+            .set(0xF88C1104, 0x10000)
+            .set(0xF8040048, 0xC0000000)
+    }
+}
+
 /// Models an upload of a binary with Memtool.
 pub struct MemtoolUpload {
     spawned: Child,
+    log_path: std::path::PathBuf,
+    timeout: Duration,
+    verify: bool,
     _temporary_files: TempDir,
 }
 
@@ -19,23 +361,67 @@ impl MemtoolUpload {
     /// be already spawned, the device to be flashed is selected based on the given
     /// UDAS port.
     ///
-    /// Note that the binary must not contain unflashable sections.
-    pub fn start(ihex: String, halt_memtool: bool, udas_port: usize) -> anyhow::Result<Self> {
+    /// The target board is described by `profile`, which selects the AURIX
+    /// family, clocking and flash banks to use instead of hardcoding a single
+    /// board.
+    ///
+    /// `connect_mode` selects whether the device is reset and flashed
+    /// normally, or attached to and downloaded into RAM without a reset.
+    ///
+    /// If `verify` is set, Memtool reads the flashed sections back and
+    /// compares them against the ihex after programming, so that a mismatch
+    /// (e.g. a bank that was not actually erased) is reported by
+    /// [`MemtoolUpload::wait`] instead of being silently missed.
+    ///
+    /// `section_filter` controls which of the ihex's sections are flashed.
+    /// By default (`SectionFilter::Automatic`) sections outside `profile`'s
+    /// flashable banks are skipped rather than passed on to Memtool, which
+    /// would otherwise abort the whole operation. With
+    /// `ConnectMode::RamOnly` the automatic filter is not applied and every
+    /// section is passed on.
+    pub fn start(
+        ihex: String,
+        halt_memtool: bool,
+        udas_port: usize,
+        profile: &BoardProfile,
+        connect_mode: ConnectMode,
+        verify: bool,
+        section_filter: SectionFilter,
+    ) -> anyhow::Result<Self> {
         let temporary_files =
             TempDir::new().context("Cannot create temporary directory for memtool input")?;
 
         let input_hex_path = temporary_files.path().join("input.hex");
 
+        let sections_to_flash = if halt_memtool {
+            Vec::new()
+        } else {
+            select_flashable_sections(&ihex, profile, &section_filter, connect_mode)?
+        };
+
         std::fs::write(&input_hex_path, ihex)
             .context("Cannot write create temporary input hex file")?;
 
         let temporary_memtool_config_path = temporary_files.path().join("temp_config.cfg");
 
-        std::fs::write(&temporary_memtool_config_path, create_cfg(udas_port))
-            .context("Cannot write create temporary memtool configuration file")?;
+        std::fs::write(
+            &temporary_memtool_config_path,
+            create_cfg(udas_port, profile, connect_mode),
+        )
+        .context("Cannot write create temporary memtool configuration file")?;
 
         let mtb = if !halt_memtool {
-            format!("connect\nopen_file {}\nselect_all_sections\nadd_selected_sections\nprogram\ndisconnect\nexit", input_hex_path.display())
+            let select_commands = sections_to_flash
+                .iter()
+                .map(|range| format!("select_section 0x{:X} 0x{:X}", range.start, range.end))
+                .collect::<Vec<_>>()
+                .join("\n");
+            let verify_step = if verify { "verify\n" } else { "" };
+            let resume = match connect_mode {
+                ConnectMode::Reset => "",
+                ConnectMode::RamOnly => "run\n",
+            };
+            format!("connect\nopen_file {}\n{select_commands}\nadd_selected_sections\nprogram\n{verify_step}{resume}disconnect\nexit", input_hex_path.display())
         } else {
             format!(
                 "connect\nopen_file {}\n",
@@ -48,11 +434,15 @@ impl MemtoolUpload {
         std::fs::write(&batch_file_path, mtb)
             .context("Cannot create temporary memtool batch file")?;
 
+        let log_path = temporary_files.path().join("memtool.log");
+
         let mut process = Command::new(env!("MEMTOOL_PATH")); // MEMTOOL_PATH is checked in the build.rs
 
         let process = process
             .arg("-c")
             .arg(temporary_memtool_config_path.display().to_string())
+            .arg("-log")
+            .arg(log_path.display().to_string())
             .arg(batch_file_path.display().to_string());
         let spawned = process
             .spawn()
@@ -61,48 +451,360 @@ impl MemtoolUpload {
 
         Ok(MemtoolUpload {
             spawned,
+            log_path,
+            timeout: DEFAULT_FLASH_TIMEOUT,
+            verify,
             _temporary_files: temporary_files,
         })
     }
 
+    /// Overrides the watchdog timeout [`MemtoolUpload::wait`] enforces
+    /// (default: [`DEFAULT_FLASH_TIMEOUT`]).
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
     /// Waits on the upload process to finish.
     ///
-    /// This can take a second, but if the tool fails execution it will hang here.
-    /// This can happen when the flash layout is broken or when another debugger
-    /// is already attached. The problem can only really be debugged with the GUI
-    /// or solved by implementing reading the logs from Memtool.
-    pub fn wait(&mut self) {
+    /// This can take a second, but if Memtool fails to make progress (for example
+    /// because the flash layout is broken or another debugger is already attached)
+    /// it can otherwise hang forever. To guard against that, a watchdog thread
+    /// kills the process if it is still running after the configured timeout,
+    /// and the Memtool log is parsed to turn the failure into an actionable
+    /// [`anyhow::Error`] instead of a panic.
+    pub fn wait(&mut self) -> anyhow::Result<()> {
+        let pid = self.spawned.id();
+        let timeout = self.timeout;
+        let (finished_tx, finished_rx) = mpsc::channel::<()>();
+
+        let watchdog = std::thread::spawn(move || {
+            if finished_rx.recv_timeout(timeout).is_ok() {
+                // wait() returned before the deadline: nothing to do.
+                return false;
+            }
+            // Still running past the deadline: kill it. The PID is only
+            // reused once the process has actually exited, so this cannot
+            // hit an unrelated process.
+            let _ = Command::new("taskkill")
+                .args(["/PID", &pid.to_string(), "/F", "/T"])
+                .status();
+            true
+        });
+
         let output = self
             .spawned
             .wait()
-            .expect("Memtool did not exit with success");
-        assert!(output.success());
+            .context("Could not wait on memtool process")?;
+
+        // Wake the watchdog so it doesn't sit around for the rest of the
+        // timeout once we already know the process exited.
+        let _ = finished_tx.send(());
+        let timed_out = watchdog.join().unwrap_or(false);
+
+        if timed_out {
+            return Err(FlashError::Timeout {
+                timeout,
+                log_tail: read_log_tail(&self.log_path),
+            }
+            .into());
+        }
+
+        if !output.success() {
+            return Err(diagnose_memtool_failure(&self.log_path).into());
+        }
+
+        // Memtool can log a verify/compare mismatch and still exit 0, so when
+        // verification was requested the log is checked regardless of the
+        // exit status.
+        if self.verify {
+            if let Some(log_tail) = find_verify_mismatch(&self.log_path) {
+                return Err(FlashError::VerifyMismatch { log_tail }.into());
+            }
+        }
+
         log::info!("Infineon Memtool terminated successfully");
+        Ok(())
+    }
+}
+
+/// A typed failure reported by [`MemtoolUpload::wait`], so that callers can
+/// tell a verify mismatch (the image flashed but does not match what was
+/// read back) apart from a program failure or a watchdog timeout.
+#[derive(Debug)]
+pub enum FlashError {
+    /// Memtool did not finish within the configured timeout and was killed.
+    Timeout { timeout: Duration, log_tail: String },
+    /// The post-program verify step found a mismatch between the ihex and
+    /// the contents read back from the device.
+    VerifyMismatch { log_tail: String },
+    /// Memtool exited with a failure unrelated to verification, e.g. an
+    /// erase, unlock or connection failure.
+    ProgramFailed { diagnosis: String },
+}
+
+impl std::fmt::Display for FlashError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlashError::Timeout { timeout, log_tail } => write!(
+                f,
+                "Memtool did not finish within {timeout:?} and was killed; log:\n{log_tail}"
+            ),
+            FlashError::VerifyMismatch { log_tail } => write!(
+                f,
+                "Memtool flashed the device but verification found a mismatch; log:\n{log_tail}"
+            ),
+            FlashError::ProgramFailed { diagnosis } => {
+                write!(f, "Memtool failed to flash the device: {diagnosis}")
+            }
+        }
     }
 }
 
+impl std::error::Error for FlashError {}
+
+/// Well-known failure patterns that show up in the Memtool log when flashing
+/// goes wrong, mapped to a human-readable diagnosis.
+const KNOWN_FAILURE_PATTERNS: &[(&str, &str)] = &[
+    (
+        "timed out waiting for target to halt",
+        "timed out waiting for the target to halt (check that no other debugger is attached)",
+    ),
+    (
+        "flash operation timed out",
+        "flash operation timed out waiting for the algorithm to complete",
+    ),
+    ("password", "device unlock/password sequence failed"),
+    ("unlock", "device unlock/password sequence failed"),
+];
+
+/// Patterns that indicate the post-program verify step found a mismatch,
+/// rather than the program step itself failing.
+const VERIFY_MISMATCH_PATTERNS: &[&str] =
+    &["verify failed", "verify mismatch", "compare failed", "does not match"];
+
+/// Scans the Memtool log for known failure patterns and returns the
+/// corresponding [`FlashError`], falling back to the raw log tail if nothing
+/// recognizable was found.
+fn diagnose_memtool_failure(log_path: &std::path::Path) -> FlashError {
+    let Ok(contents) = std::fs::read_to_string(log_path) else {
+        return FlashError::ProgramFailed {
+            diagnosis: "no Memtool log was produced".to_string(),
+        };
+    };
+
+    if let Some(log_tail) = find_verify_mismatch_in(&contents) {
+        return FlashError::VerifyMismatch { log_tail };
+    }
+
+    let lowercased = contents.to_lowercase();
+    for (pattern, diagnosis) in KNOWN_FAILURE_PATTERNS {
+        if lowercased.contains(pattern) {
+            return FlashError::ProgramFailed {
+                diagnosis: diagnosis.to_string(),
+            };
+        }
+    }
+
+    FlashError::ProgramFailed {
+        diagnosis: format!("see log:\n{}", tail(&contents)),
+    }
+}
+
+/// Reads the Memtool log at `log_path` and returns its tail if it contains a
+/// verify/compare mismatch, or `None` if it doesn't (or couldn't be read).
+fn find_verify_mismatch(log_path: &std::path::Path) -> Option<String> {
+    find_verify_mismatch_in(&std::fs::read_to_string(log_path).ok()?)
+}
+
+/// Returns the tail of `contents` if it contains a verify/compare mismatch.
+fn find_verify_mismatch_in(contents: &str) -> Option<String> {
+    let lowercased = contents.to_lowercase();
+    VERIFY_MISMATCH_PATTERNS
+        .iter()
+        .any(|pattern| lowercased.contains(pattern))
+        .then(|| tail(contents))
+}
+
+/// Reads the last few lines of the Memtool log, or a placeholder if it could
+/// not be read (e.g. Memtool was killed before it created the file).
+fn read_log_tail(log_path: &std::path::Path) -> String {
+    match std::fs::read_to_string(log_path) {
+        Ok(contents) => tail(&contents),
+        Err(_) => "<no log available>".to_string(),
+    }
+}
+
+/// Returns the last 20 lines of `contents`.
+fn tail(contents: &str) -> String {
+    let lines: Vec<&str> = contents.lines().collect();
+    let start = lines.len().saturating_sub(20);
+    lines[start..].join("\n")
+}
+
+/// Parses `ihex`'s sections and applies `section_filter` to them, logging
+/// and dropping any section that should not be flashed.
+fn select_flashable_sections(
+    ihex: &str,
+    profile: &BoardProfile,
+    section_filter: &SectionFilter,
+    connect_mode: ConnectMode,
+) -> anyhow::Result<Vec<AddressRange>> {
+    let sections = parse_ihex_sections(ihex)?;
+
+    // A RAM-download image has no sections inside any flash bank by
+    // definition, so the default filter would reject everything. Skip it
+    // and let the caller's explicit Allow/Deny filters still apply.
+    if matches!(section_filter, SectionFilter::Automatic) && connect_mode == ConnectMode::RamOnly {
+        return Ok(sections);
+    }
+
+    let flashable_ranges = profile.flashable_ranges();
+
+    let (keep, skip): (Vec<_>, Vec<_>) = sections.into_iter().partition(|section| match section_filter {
+        SectionFilter::Automatic => flashable_ranges.iter().any(|bank| bank.contains(section)),
+        SectionFilter::Allow(allowed) => allowed.iter().any(|range| range.contains(section)),
+        SectionFilter::Deny(denied) => !denied.iter().any(|range| range.overlaps(section)),
+    });
+
+    let skip_reason = match section_filter {
+        SectionFilter::Automatic => "outside the board's flashable address ranges",
+        SectionFilter::Allow(_) => "not covered by any allowed address range",
+        SectionFilter::Deny(_) => "overlaps a denied address range",
+    };
+    for section in &skip {
+        log::warn!(
+            "Skipping ihex section 0x{:X}..0x{:X}: {skip_reason}",
+            section.start,
+            section.end
+        );
+    }
+
+    anyhow::ensure!(
+        !keep.is_empty(),
+        "None of the ihex's sections fall inside a flashable address range"
+    );
+
+    Ok(keep)
+}
+
+/// Selects which sections of an ihex get flashed.
+#[derive(Debug, Clone, Default)]
+pub enum SectionFilter {
+    /// Skip sections that fall outside the board profile's flashable banks.
+    /// Not applied with `ConnectMode::RamOnly`, where every section is kept.
+    #[default]
+    Automatic,
+    /// Only flash sections that fall entirely inside one of these ranges.
+    Allow(Vec<AddressRange>),
+    /// Flash every section except ones overlapping one of these ranges.
+    Deny(Vec<AddressRange>),
+}
+
+/// Parses the address ranges covered by an ihex's data records, merging
+/// adjacent/overlapping ones, so they can be checked against a board's flash
+/// memory map before flashing.
+fn parse_ihex_sections(ihex: &str) -> anyhow::Result<Vec<AddressRange>> {
+    let mut ranges = Vec::new();
+    let mut upper_linear: u32 = 0;
+    let mut upper_segment: u32 = 0;
+
+    for line in ihex.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let record = line
+            .strip_prefix(':')
+            .with_context(|| format!("ihex record missing ':' prefix: {line}"))?;
+        let bytes = decode_hex(record)?;
+        anyhow::ensure!(bytes.len() >= 5, "ihex record too short: {line}");
+
+        let (fields, checksum) = bytes.split_at(bytes.len() - 1);
+        let computed_checksum = fields.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        anyhow::ensure!(
+            computed_checksum.wrapping_add(checksum[0]) == 0,
+            "ihex record failed its checksum (possible data corruption): {line}"
+        );
+
+        let byte_count = fields[0] as usize;
+        let address = u16::from_be_bytes([fields[1], fields[2]]) as u32;
+        let record_type = fields[3];
+        let data = fields
+            .get(4..4 + byte_count)
+            .with_context(|| format!("ihex record shorter than its byte count: {line}"))?;
+
+        match record_type {
+            0x00 => {
+                let start = upper_linear.wrapping_add(upper_segment).wrapping_add(address);
+                let end = start
+                    .checked_add(data.len() as u32)
+                    .with_context(|| format!("ihex record's address range overflows a 32-bit address: {line}"))?;
+                ranges.push(AddressRange { start, end });
+            }
+            0x01 => break,
+            0x02 => {
+                anyhow::ensure!(data.len() == 2, "malformed extended segment address record");
+                upper_segment = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4;
+            }
+            0x04 => {
+                anyhow::ensure!(data.len() == 2, "malformed extended linear address record");
+                upper_linear = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(merge_ranges(ranges))
+}
+
+/// Decodes a string of hex digit pairs into bytes.
+fn decode_hex(s: &str) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(s.len().is_multiple_of(2), "hex string has an odd length: {s}");
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).with_context(|| format!("invalid hex digits in {s}")))
+        .collect()
+}
+
+/// Sorts and merges overlapping/adjacent address ranges.
+fn merge_ranges(mut ranges: Vec<AddressRange>) -> Vec<AddressRange> {
+    ranges.sort_by_key(|range| range.start);
+
+    let mut merged: Vec<AddressRange> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
 /// Creates a Memtool configuration.
 ///
 /// The configuration file is templated based on the default configuration in Memtool
-/// from the TC37xA family, but the DAS port can be selected freely.
-fn create_cfg(udas_port: usize) -> String {
+/// from the TC37xA family, but the target `profile` (device family, clocking
+/// and enabled flash banks) and the `connect_mode` (reset and flash, or attach
+/// and download to RAM) can be selected freely. `udas_port` is not written
+/// into the configuration; it always uses `DasPortSel=0`.
+fn create_cfg(udas_port: usize, profile: &BoardProfile, connect_mode: ConnectMode) -> String {
     format!(
 "[Main]
 Signature=UDE_TARGINFO_2.0
 MCUs=Controller0
-Description=Triboard with TC39x B-Step (DAS)
-Description1=Init TLF35584 C-Step on connect
-Description2=switch off FLASH error traps
+Description={description}
 Architecture=TriCore Aurix2G
 Vendor=Starter Kits (DAS)
 Board=
 
 [Controller0]
 Family=TriCore
-Type=TC39xB
+Type={family_type}
 Enabled=1
-IntClock=100000
-ExtClock=20000
+IntClock={int_clock}
+ExtClock={ext_clock}
 
 [Controller0.Core0]
 Protocol=TC2_JTAG
@@ -117,7 +819,7 @@ CommDevSel=
 MaxJtagClk=5000
 DasTryStartSrv=1
 DasSrvPath=servers\\udas\\udas.exe
-ConnOption=Reset
+ConnOption={conn_option}
 DiswdtOnReset=1
 ExecInitCmds=1
 TargetPort=Default
@@ -167,8 +869,8 @@ MaxTry=1
 UseDflashAccessFilter=1
 DetectResetWhileHalted=1
 UseTranslateAddr=1
-DownloadToAllRams=0
-HaltAfterReset=0
+DownloadToAllRams={download_to_all_rams}
+HaltAfterReset={halt_after_reset}
 HaltAfterHardwareReset=0
 TargetAppHandshakeMode=None
 TargetAppHandshakeTimeout=100
@@ -207,88 +909,10 @@ DasPortSel=0
 DasCmdTimeout=1000
 DasWaitAfterConnect=0
 DasDisconnectSrv=0
-DasApiLogging=0
+DasApiLogging=1
 
 [Controller0.Core0.Tc2CoreTargIntf.InitScript]
-; Init TLF35584 C-Step on connect
-SET 0xF0036034  0x11100002
-SET 0xF0001E00  0x8
-SET 0xF0001E10  0x20003C04
-SET 0xF0001E04  0x1
-SET 0xF0001E14  0x14000000
-SET 0xF0001E24  0x501
-SET 0xF0001E48  0x00020000
-SET 0xF003AF10  0x98000000
-SET 0xF003AF14  0x10980000
-SET 0xF003AF40  0x30330333
-SET 0xF003AE10  0x10980000
-SET 0xF003AE40  0x33333033
-WAIT 5
-SET 0xF0001E54  0xFFF
-SET 0xF0001E60  0x17A10001
-SET 0xF0001E54 0x200
-WAIT 5
-SET 0xF0001E10  0x21003C04
-SET 0xF0001E64 0x8756
-WAIT 5
-SET 0xF0001E54 0x200
-WAIT 5
-SET 0xF0001E54 0x400
-SET 0xF0001E64 0x87DE
-WAIT 5
-SET 0xF0001E54 0x200
-WAIT 5
-SET 0xF0001E54 0x400
-SET 0xF0001E64 0x86AD
-WAIT 5
-SET 0xF0001E54 0x200
-WAIT 5
-SET 0xF0001E54 0x400
-SET 0xF0001E64 0x8625
-WAIT 5
-SET 0xF0001E54 0x200
-WAIT 5
-SET 0xF0001E54 0x400
-SET 0xF0001E64 0x8D27
-WAIT 5
-SET 0xF0001E54 0x200
-WAIT 5
-SET 0xF0001E54 0x400
-SET 0xF0001E64 0x8A01
-WAIT 5
-SET 0xF0001E54 0x200
-WAIT 5
-SET 0xF0001E54 0x400
-SET 0xF0001E64 0x87BE
-WAIT 5
-SET 0xF0001E54 0x200
-WAIT 5
-SET 0xF0001E54 0x400
-SET 0xF0001E64 0x8668
-WAIT 5
-SET 0xF0001E54 0x200
-WAIT 5
-SET 0xF0001E54 0x400
-SET 0xF0001E64 0x877D
-WAIT 5
-SET 0xF0001E54 0x200
-WAIT 5
-SET 0xF0001E54 0x400
-SET 0xF0001E64 0x8795
-WAIT 5
-SET 0xF0001E54 0x200
-WAIT 5
-SET 0xF0001E54 0x400
-WAIT 5
-
-; switch off FLASH error traps
-set 0xF8801104 0x10000
-set 0xF8821104 0x10000
-set 0xF8841104 0x10000
-set 0xF8861104 0x10000
-set 0xF8881104 0x10000
-set 0xF88C1104 0x10000
-set 0xF8040048 0xC0000000
+{init_script}
 
 [Controller0.Core0.Tc2CoreTargIntf.OnStartScript]
 
@@ -303,18 +927,178 @@ STM4=1
 STM5=1
 
 [Controller0.PFLASH]
-Enabled=1
-EnableMemtoolByDefault=1
+Enabled={pflash}
+EnableMemtoolByDefault={pflash}
 
 [Controller0.DF_EEPROM]
-Enabled=1
-EnableMemtoolByDefault=1
+Enabled={df_eeprom}
+EnableMemtoolByDefault={df_eeprom}
 
 [Controller0.DF_UCBS]
-Enabled=1
-EnableMemtoolByDefault=1
+Enabled={df_ucbs}
+EnableMemtoolByDefault={df_ucbs}
 
 
-[Controller0.Core0.Tc2CoreTargIntf.OnConnectScript]"
+[Controller0.Core0.Tc2CoreTargIntf.OnConnectScript]",
+        description = profile.description,
+        family_type = profile.family.memtool_type(),
+        int_clock = profile.int_clock,
+        ext_clock = profile.ext_clock,
+        pflash = profile.pflash as u8,
+        df_eeprom = profile.df_eeprom as u8,
+        df_ucbs = profile.df_ucbs as u8,
+        init_script = profile.init_script.render(),
+        conn_option = connect_mode.conn_option(),
+        download_to_all_rams = connect_mode.download_to_all_rams(),
+        halt_after_reset = connect_mode.halt_after_reset(),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tc39x_pflash_extent_is_16mb() {
+        let pflash = DeviceFamily::Tc39x
+            .flash_banks()
+            .into_iter()
+            .find(|bank| bank.name == "PFLASH" && bank.range.start == 0x8000_0000)
+            .expect("TC39x has a non-cached PFLASH bank");
+        assert_eq!(pflash.range.end, 0x8100_0000);
+    }
+
+    #[test]
+    fn tc39x_pflash_has_a_cached_alias() {
+        let pflash = DeviceFamily::Tc39x
+            .flash_banks()
+            .into_iter()
+            .find(|bank| bank.name == "PFLASH" && bank.range.start == 0xA000_0000)
+            .expect("TC39x has a cached PFLASH bank");
+        assert_eq!(pflash.range.end, 0xA100_0000);
+    }
+
+    /// Builds a single well-formed ihex record line with a correct checksum,
+    /// so tests don't have to hand-compute one.
+    fn ihex_record(address: u16, record_type: u8, data: &[u8]) -> String {
+        let mut fields = vec![data.len() as u8];
+        fields.extend_from_slice(&address.to_be_bytes());
+        fields.push(record_type);
+        fields.extend_from_slice(data);
+        let checksum = 0u8.wrapping_sub(fields.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)));
+
+        let mut line = String::from(":");
+        for byte in fields.iter().chain(std::iter::once(&checksum)) {
+            line.push_str(&format!("{byte:02X}"));
+        }
+        line
+    }
+
+    #[test]
+    fn parses_a_simple_data_record() {
+        let ihex = format!("{}\n{}", ihex_record(0x0000, 0x00, &[0xAA, 0xBB]), ihex_record(0x0000, 0x01, &[]));
+        let sections = parse_ihex_sections(&ihex).unwrap();
+        assert_eq!(sections, vec![AddressRange { start: 0, end: 2 }]);
+    }
+
+    #[test]
+    fn rejects_a_record_with_a_corrupted_checksum() {
+        let mut record = ihex_record(0x0000, 0x00, &[0xAA]);
+        let last = record.pop().unwrap();
+        record.push(if last == '0' { '1' } else { '0' }); // flip the checksum's last digit
+        assert!(parse_ihex_sections(&record).is_err());
+    }
+
+    #[test]
+    fn applies_extended_linear_address() {
+        let ihex = format!(
+            "{}\n{}",
+            ihex_record(0x0000, 0x04, &[0x00, 0x01]), // upper 16 bits = 0x0001
+            ihex_record(0x0010, 0x00, &[0x01, 0x02, 0x03, 0x04]),
+        );
+        let sections = parse_ihex_sections(&ihex).unwrap();
+        assert_eq!(sections, vec![AddressRange { start: 0x0001_0010, end: 0x0001_0014 }]);
+    }
+
+    #[test]
+    fn applies_extended_segment_address() {
+        let ihex = format!(
+            "{}\n{}",
+            ihex_record(0x0000, 0x02, &[0x10, 0x00]), // segment 0x1000, shifted << 4
+            ihex_record(0x0000, 0x00, &[0xFF]),
+        );
+        let sections = parse_ihex_sections(&ihex).unwrap();
+        assert_eq!(sections, vec![AddressRange { start: 0x0001_0000, end: 0x0001_0001 }]);
+    }
+
+    #[test]
+    fn rejects_a_record_whose_range_overflows_u32() {
+        let ihex = format!(
+            "{}\n{}",
+            ihex_record(0x0000, 0x04, &[0xFF, 0xFF]), // upper 16 bits = 0xFFFF0000
+            ihex_record(0xFFFF, 0x00, &[0x01, 0x02]), // start = 0xFFFFFFFF, + 2 bytes overflows
+        );
+        assert!(parse_ihex_sections(&ihex).is_err());
+    }
+
+    #[test]
+    fn merge_ranges_joins_adjacent_and_overlapping() {
+        let ranges = vec![
+            AddressRange { start: 0, end: 10 },
+            AddressRange { start: 10, end: 20 },
+            AddressRange { start: 15, end: 18 },
+            AddressRange { start: 100, end: 110 },
+        ];
+        assert_eq!(
+            merge_ranges(ranges),
+            vec![AddressRange { start: 0, end: 20 }, AddressRange { start: 100, end: 110 }]
+        );
+    }
+
+    #[test]
+    fn address_range_contains_and_overlaps() {
+        let bank = AddressRange { start: 0x8000_0000, end: 0x8010_0000 };
+        assert!(bank.contains(&AddressRange { start: 0x8000_0000, end: 0x8000_1000 }));
+        assert!(!bank.contains(&AddressRange { start: 0x8000_0000, end: 0x8020_0000 }));
+        assert!(bank.overlaps(&AddressRange { start: 0x7FFF_FFF0, end: 0x8000_0010 }));
+        assert!(!bank.overlaps(&AddressRange { start: 0x8010_0000, end: 0x8020_0000 }));
+    }
+
+    #[test]
+    fn init_script_renders_steps_in_order() {
+        let script = InitScript::new()
+            .comment("hi")
+            .set(0x1000, 0x2)
+            .wait(5)
+            .blank()
+            .comment("bye");
+        assert_eq!(script.render(), "; hi\nSET 0x00001000 0x2\nWAIT 5\n\n; bye");
+    }
+
+    #[test]
+    fn finds_a_verify_mismatch_case_insensitively() {
+        assert!(find_verify_mismatch_in("Flash OK\nVERIFY FAILED at 0x8000000\n").is_some());
+        assert!(find_verify_mismatch_in("Flash OK\nAll good\n").is_none());
+    }
+
+    #[test]
+    fn diagnoses_a_known_failure_pattern_from_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("memtool.log");
+        std::fs::write(&log_path, "Connecting...\nError: device unlock failed\n").unwrap();
+
+        let error = diagnose_memtool_failure(&log_path);
+        assert!(matches!(error, FlashError::ProgramFailed { .. }));
+        assert!(error.to_string().contains("unlock"));
+    }
+
+    #[test]
+    fn diagnoses_a_verify_mismatch_over_a_generic_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("memtool.log");
+        std::fs::write(&log_path, "Connecting...\nprogram: verify mismatch at 0x8000000\n").unwrap();
+
+        let error = diagnose_memtool_failure(&log_path);
+        assert!(matches!(error, FlashError::VerifyMismatch { .. }));
+    }
+}